@@ -1,7 +1,11 @@
+use std::collections::HashMap;
 use std::fs::File;
 use std::io::Write;
-use std::path::Path;
+use std::path::{Path, PathBuf};
 use std::process::{Command, Stdio};
+use std::sync::mpsc;
+use std::sync::{Arc, Mutex};
+use std::thread;
 
 use anyhow::{anyhow, Context};
 use clap::{ArgGroup, Parser};
@@ -20,10 +24,135 @@ struct Args {
     #[arg(long("self"), group = "u")]
     self_user: bool,
 
+    /// Mirror multiple sources as declared in a TOML or JSON config file, instead of a
+    /// single source given on the command line.
+    #[arg(long, group = "u")]
+    config: Option<PathBuf>,
+
+    /// GitHub organization name
+    #[arg(long, group = "u")]
+    org: Option<String>,
+
+    /// GitHub Enterprise Server hostname to target, e.g. `github.example.com`. Passed
+    /// through to `gh api --hostname`.
+    #[arg(long)]
+    host: Option<String>,
+
+    /// Only mirror repositories with this visibility (e.g. `public`, `private`,
+    /// `internal`). Only supported with `--self`; GitHub's `user/repos`
+    /// endpoint is the only one that accepts this filter.
+    #[arg(long)]
+    visibility: Option<String>,
+
+    /// Only mirror repositories with this affiliation to the source (e.g. `owner`,
+    /// `collaborator`, `organization_member`). Only supported with `--self`; GitHub's
+    /// `user/repos` endpoint is the only one that accepts this filter.
+    #[arg(long)]
+    affiliation: Option<String>,
+
+    /// Push each mirrored repo to a downstream remote, in addition to mirroring it
+    /// locally. `{name}` is replaced with the repo's name. May be given more than once
+    /// to push to several remotes, e.g.
+    /// `--push-to git@gitlab.example.com:mirrors/{name}.git`.
+    #[arg(long = "push-to")]
+    push_to: Vec<String>,
+
+    /// Number of repos to clone/update concurrently.
+    #[arg(long, default_value_t = 1)]
+    jobs: usize,
+
+    /// Email address to send a digest of newly fetched commits to after each update.
+    #[arg(long)]
+    notify_to: Option<String>,
+
+    /// Command to pipe the notification email to, in `sendmail -t` style (reads an
+    /// RFC822 message with headers on stdin).
+    #[arg(long, default_value = "sendmail")]
+    sendmail_cmd: String,
+
     #[arg(long)]
     dry_run: bool,
 }
 
+/// Where to pull repositories from.
+#[derive(Debug, Clone, Copy)]
+enum Source<'a> {
+    User(&'a str),
+    SelfUser,
+    Org(&'a str),
+}
+
+impl Source<'_> {
+    fn endpoint(&self) -> String {
+        match self {
+            Source::User(user) => format!("users/{user}/repos"),
+            Source::SelfUser => "user/repos".to_owned(),
+            Source::Org(org) => format!("orgs/{org}/repos"),
+        }
+    }
+}
+
+impl std::fmt::Display for Source<'_> {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            Source::User(user) => write!(f, "user {user}"),
+            Source::SelfUser => write!(f, "the authenticated user"),
+            Source::Org(org) => write!(f, "org {org}"),
+        }
+    }
+}
+
+/// A config file declaring a workspace directory and the sources to mirror into it.
+#[derive(Debug, Deserialize)]
+struct Config {
+    /// Root directory that entries are mirrored under.
+    workspace: PathBuf,
+
+    #[serde(default)]
+    entries: Vec<ConfigEntry>,
+}
+
+/// One source to mirror, and where under the workspace its repos should land.
+#[derive(Debug, Deserialize)]
+struct ConfigEntry {
+    /// GitHub username.
+    user: Option<String>,
+
+    /// Mirror repositories for the logged-in user, including private repos.
+    #[serde(default)]
+    self_user: bool,
+
+    /// GitHub organization name.
+    org: Option<String>,
+
+    /// Subdirectory under the workspace to clone this entry's repos into. Defaults to the
+    /// workspace root.
+    subdir: Option<PathBuf>,
+}
+
+impl ConfigEntry {
+    fn source(&self) -> anyhow::Result<Source<'_>> {
+        match (&self.user, self.self_user, &self.org) {
+            (Some(user), false, None) => Ok(Source::User(user)),
+            (None, true, None) => Ok(Source::SelfUser),
+            (None, false, Some(org)) => Ok(Source::Org(org)),
+            _ => Err(anyhow!(
+                "config entry must specify exactly one of user, self_user, or org"
+            )),
+        }
+    }
+}
+
+fn load_config(path: &Path) -> anyhow::Result<Config> {
+    let text = std::fs::read_to_string(path).with_context(|| format!("failed to read {path:?}"))?;
+    match path.extension().and_then(|e| e.to_str()) {
+        Some("json") => {
+            serde_json::from_str(&text).with_context(|| format!("failed to parse {path:?}"))
+        }
+        _ => toml::from_str(&text).with_context(|| format!("failed to parse {path:?}")),
+    }
+}
+
 #[derive(Debug, Deserialize)]
 struct Repository {
     name: String,
@@ -48,15 +177,38 @@ impl std::fmt::Display for Error {
     }
 }
 
-fn get_repositories(user: Option<&str>) -> anyhow::Result<impl Iterator<Item = Repository>> {
-    let out = Command::new("gh")
-        .arg("api")
-        .arg("--paginate")
-        .arg(if let Some(user) = user {
-            format!("users/{user}/repos")
-        } else {
-            "user/repos".to_owned()
-        })
+fn get_repositories(
+    source: Source,
+    host: Option<&str>,
+    visibility: Option<&str>,
+    affiliation: Option<&str>,
+) -> anyhow::Result<impl Iterator<Item = Repository>> {
+    if !matches!(source, Source::SelfUser) && (visibility.is_some() || affiliation.is_some()) {
+        return Err(anyhow!(
+            "--visibility/--affiliation are only supported with --self; \
+             GitHub's users/{{user}}/repos and orgs/{{org}}/repos endpoints don't accept them"
+        ));
+    }
+
+    let mut query = source.endpoint();
+    let mut params = Vec::new();
+    if let Some(visibility) = visibility {
+        params.push(format!("visibility={visibility}"));
+    }
+    if let Some(affiliation) = affiliation {
+        params.push(format!("affiliation={affiliation}"));
+    }
+    if !params.is_empty() {
+        query = format!("{query}?{}", params.join("&"));
+    }
+
+    let mut cmd = Command::new("gh");
+    cmd.arg("api").arg("--paginate");
+    if let Some(host) = host {
+        cmd.arg("--hostname").arg(host);
+    }
+    let out = cmd
+        .arg(query)
         .stderr(Stdio::inherit())
         .output()
         .context("failed to run gh api")?;
@@ -67,7 +219,7 @@ fn get_repositories(user: Option<&str>) -> anyhow::Result<impl Iterator<Item = R
                 |e| Err(e).context("failed to deserialize error"),
                 |e| Err(anyhow!(e)),
             )
-            .with_context(|| format!("failed to list repositories for user {user:?}"));
+            .with_context(|| format!("failed to list repositories for {source}"));
     }
 
     Ok(Deserializer::from_slice(&out.stdout)
@@ -79,7 +231,7 @@ fn get_repositories(user: Option<&str>) -> anyhow::Result<impl Iterator<Item = R
 }
 
 fn git_clone(path: &Path, url: &str) -> anyhow::Result<()> {
-    Command::new("git")
+    let status = Command::new("git")
         .arg("clone")
         .arg("--mirror")
         .arg("--origin")
@@ -87,7 +239,10 @@ fn git_clone(path: &Path, url: &str) -> anyhow::Result<()> {
         .arg(url)
         .arg(path)
         .status()
-        .with_context(|| format!("failed to git clone {url}"))?;
+        .with_context(|| format!("failed to run git clone {url}"))?;
+    if !status.success() {
+        return Err(anyhow!("git clone {url} failed: {status}"));
+    }
 
     let mut hook = File::create(path.join("hooks").join("pre-receive"))
         .context("failed to create hooks/pre-receive")?;
@@ -114,36 +269,383 @@ fn git_clone(path: &Path, url: &str) -> anyhow::Result<()> {
 }
 
 fn git_update(path: &Path) -> anyhow::Result<()> {
-    Command::new("git")
+    let status = Command::new("git")
         .arg("-C")
         .arg(path)
         .arg("remote")
         .arg("update")
         .arg("--prune")
         .status()
-        .with_context(|| format!("failed to git remote update {path:?}"))?;
+        .with_context(|| format!("failed to run git remote update {path:?}"))?;
+    if !status.success() {
+        return Err(anyhow!("git remote update {path:?} failed: {status}"));
+    }
     Ok(())
 }
 
-fn main() -> anyhow::Result<()> {
-    let args = Args::parse();
-    let root = std::env::current_dir().context("failed to get cwd")?;
-    for repo in get_repositories(args.user.as_deref())? {
-        if args.dry_run {
-            eprintln!("{repo:?}");
+/// How many commits to show for a ref that's new since the last update (there's no
+/// "old" SHA to start a range from).
+const NEW_REF_LOG_LIMIT: &str = "20";
+
+/// The SHA each ref in `path` currently points to, keyed by ref name.
+fn capture_refs(path: &Path) -> anyhow::Result<HashMap<String, String>> {
+    let out = Command::new("git")
+        .arg("-C")
+        .arg(path)
+        .arg("show-ref")
+        .output()
+        .with_context(|| format!("failed to show-ref {path:?}"))?;
+    // show-ref exits nonzero when the repo has no refs yet; that's not an error here.
+    Ok(String::from_utf8_lossy(&out.stdout)
+        .lines()
+        .filter_map(|line| line.split_once(' '))
+        .map(|(sha, name)| (name.to_owned(), sha.to_owned()))
+        .collect())
+}
+
+/// Render a digest of the commits introduced between `before` and `after`, or `None` if
+/// nothing changed.
+fn format_notification(
+    path: &Path,
+    before: &HashMap<String, String>,
+    after: &HashMap<String, String>,
+) -> anyhow::Result<Option<String>> {
+    let mut body = String::new();
+    for (name, new_sha) in after {
+        let old_sha = before.get(name);
+        if old_sha == Some(new_sha) {
+            continue;
         }
-        let path = root.join(&repo.name);
-        if path.is_dir() {
-            println!("updating {}", repo.name);
-            if !args.dry_run {
-                git_update(&path)?;
+
+        let mut cmd = Command::new("git");
+        cmd.arg("-C").arg(path).arg("log").arg("--pretty");
+        match old_sha {
+            Some(old_sha) => {
+                cmd.arg(format!("{old_sha}..{new_sha}"));
+            }
+            None => {
+                cmd.arg("-n").arg(NEW_REF_LOG_LIMIT).arg(new_sha);
+            }
+        }
+        let out = cmd
+            .output()
+            .with_context(|| format!("failed to git log {name} in {path:?}"))?;
+
+        body.push_str(&format!("=== {name} ===\n"));
+        body.push_str(&String::from_utf8_lossy(&out.stdout));
+        body.push('\n');
+    }
+    Ok(if body.is_empty() { None } else { Some(body) })
+}
+
+/// Deliver (or, in dry-run mode, print) a notification email about `repo_name`'s new
+/// commits.
+fn send_notification(
+    to: &str,
+    sendmail_cmd: &str,
+    repo_name: &str,
+    body: &str,
+    dry_run: bool,
+) -> anyhow::Result<()> {
+    let message = format!(
+        "From: gh-mirror <gh-mirror@localhost>\n\
+         To: {to}\n\
+         Subject: [gh-mirror] new commits in {repo_name}\n\
+         \n\
+         {body}"
+    );
+
+    if dry_run {
+        eprintln!("{message}");
+        return Ok(());
+    }
+
+    let mut child = Command::new(sendmail_cmd)
+        .arg("-t")
+        .stdin(Stdio::piped())
+        .spawn()
+        .with_context(|| format!("failed to spawn {sendmail_cmd:?}"))?;
+    child
+        .stdin
+        .take()
+        .expect("child spawned with piped stdin")
+        .write_all(message.as_bytes())
+        .context("failed to write notification to sendmail's stdin")?;
+    child
+        .wait()
+        .with_context(|| format!("failed to wait on {sendmail_cmd:?}"))?;
+    Ok(())
+}
+
+fn git_remote_names(path: &Path) -> anyhow::Result<Vec<String>> {
+    let out = Command::new("git")
+        .arg("-C")
+        .arg(path)
+        .arg("remote")
+        .output()
+        .with_context(|| format!("failed to list remotes for {path:?}"))?;
+    Ok(String::from_utf8_lossy(&out.stdout)
+        .lines()
+        .map(|l| l.to_owned())
+        .collect())
+}
+
+/// FNV-1a, a small fixed hash algorithm (unlike `std::hash::DefaultHasher`, whose output
+/// isn't guaranteed stable across Rust versions or even across runs of the same binary).
+fn fnv1a_64(data: &[u8]) -> u64 {
+    const OFFSET_BASIS: u64 = 0xcbf29ce484222325;
+    const PRIME: u64 = 0x0000_0100_0000_01b3;
+    data.iter().fold(OFFSET_BASIS, |hash, &byte| {
+        (hash ^ u64::from(byte)).wrapping_mul(PRIME)
+    })
+}
+
+/// A stable remote name for a `--push-to` template, so that the remote a repo pushes to
+/// stays bound to the template that created it even if templates are reordered, added,
+/// or removed between runs (or the binary is rebuilt with a different toolchain).
+fn remote_name_for_template(template: &str) -> String {
+    format!("mirror-{:016x}", fnv1a_64(template.as_bytes()))
+}
+
+/// Push a mirrored repo to a downstream remote, adding the remote first if it isn't
+/// already configured.
+fn push_mirror(path: &Path, remote_name: &str, url: &str, dry_run: bool) -> anyhow::Result<()> {
+    if dry_run {
+        eprintln!("would push {path:?} to {remote_name} ({url})");
+        return Ok(());
+    }
+
+    if !git_remote_names(path)?.iter().any(|r| r == remote_name) {
+        let status = Command::new("git")
+            .arg("-C")
+            .arg(path)
+            .arg("remote")
+            .arg("add")
+            .arg(remote_name)
+            .arg(url)
+            .status()
+            .with_context(|| format!("failed to run git remote add {remote_name} in {path:?}"))?;
+        if !status.success() {
+            return Err(anyhow!(
+                "git remote add {remote_name} {url} in {path:?} failed: {status}"
+            ));
+        }
+    }
+
+    let status = Command::new("git")
+        .arg("-C")
+        .arg(path)
+        .arg("push")
+        .arg("--mirror")
+        .arg(remote_name)
+        .status()
+        .with_context(|| format!("failed to run git push --mirror {remote_name} from {path:?}"))?;
+    if !status.success() {
+        return Err(anyhow!(
+            "git push --mirror {remote_name} from {path:?} failed: {status}"
+        ));
+    }
+    Ok(())
+}
+
+/// Clone or update a single repo into `root`, then push it to the configured `push_to`
+/// remote templates. Log lines are tagged with the repo's name so concurrent workers'
+/// output stays attributable.
+#[allow(clippy::too_many_arguments)]
+fn mirror_one(
+    repo: &Repository,
+    root: &Path,
+    dry_run: bool,
+    push_to: &[String],
+    notify_to: Option<&str>,
+    sendmail_cmd: &str,
+) -> anyhow::Result<()> {
+    let path = root.join(&repo.name);
+    if path.is_dir() {
+        println!("[{}] updating", repo.name);
+        if dry_run {
+            if let Some(to) = notify_to {
+                eprintln!(
+                    "[{}] would check for new commits and notify {to}",
+                    repo.name
+                );
             }
         } else {
-            println!("cloning {}", repo.name);
-            if !args.dry_run {
-                git_clone(&path, &repo.ssh_url)?;
+            let before = match notify_to {
+                Some(_) => capture_refs(&path)?,
+                None => HashMap::new(),
+            };
+            git_update(&path)?;
+            if let Some(to) = notify_to {
+                let after = capture_refs(&path)?;
+                if let Some(body) = format_notification(&path, &before, &after)? {
+                    if let Err(e) = send_notification(to, sendmail_cmd, &repo.name, &body, dry_run)
+                    {
+                        eprintln!(
+                            "[{}] warning: failed to send notification: {e:#}",
+                            repo.name
+                        );
+                    }
+                }
             }
         }
+    } else {
+        println!("[{}] cloning", repo.name);
+        if !dry_run {
+            git_clone(&path, &repo.ssh_url)?;
+        }
+    }
+
+    for template in push_to {
+        let remote_name = remote_name_for_template(template);
+        let url = template.replace("{name}", &repo.name);
+        if let Err(e) = push_mirror(&path, &remote_name, &url, dry_run) {
+            eprintln!(
+                "[{}] warning: failed to push to {remote_name}: {e:#}",
+                repo.name
+            );
+        }
+    }
+    Ok(())
+}
+
+/// Clone or update every repo from `source` into `root` using a pool of `jobs` worker
+/// threads, isolating each repo's failure (or panic) so the rest of the run continues.
+/// Returns the number of repos that failed to mirror.
+#[allow(clippy::too_many_arguments)]
+fn mirror_source(
+    source: Source,
+    root: &Path,
+    host: Option<&str>,
+    visibility: Option<&str>,
+    affiliation: Option<&str>,
+    dry_run: bool,
+    push_to: &[String],
+    jobs: usize,
+    notify_to: Option<&str>,
+    sendmail_cmd: &str,
+) -> anyhow::Result<usize> {
+    let repos: Vec<Repository> = get_repositories(source, host, visibility, affiliation)?.collect();
+    if dry_run {
+        for repo in &repos {
+            eprintln!("{repo:?}");
+        }
+    }
+
+    let (work_tx, work_rx) = mpsc::channel();
+    for repo in repos {
+        work_tx.send(repo).expect("receiver not yet dropped");
+    }
+    drop(work_tx);
+    let work_rx = Arc::new(Mutex::new(work_rx));
+
+    let root = Arc::new(root.to_path_buf());
+    let push_to = Arc::new(push_to.to_vec());
+    let notify_to = Arc::new(notify_to.map(str::to_owned));
+    let sendmail_cmd = Arc::new(sendmail_cmd.to_owned());
+    let (result_tx, result_rx) = mpsc::channel();
+    let workers: Vec<_> = (0..jobs.max(1))
+        .map(|_| {
+            let work_rx = Arc::clone(&work_rx);
+            let root = Arc::clone(&root);
+            let push_to = Arc::clone(&push_to);
+            let notify_to = Arc::clone(&notify_to);
+            let sendmail_cmd = Arc::clone(&sendmail_cmd);
+            let result_tx = result_tx.clone();
+            thread::spawn(move || loop {
+                let repo = match work_rx.lock().expect("worker mutex poisoned").recv() {
+                    Ok(repo) => repo,
+                    Err(_) => break,
+                };
+                let name = repo.name.clone();
+                let result = std::panic::catch_unwind(std::panic::AssertUnwindSafe(|| {
+                    mirror_one(
+                        &repo,
+                        &root,
+                        dry_run,
+                        &push_to,
+                        notify_to.as_deref(),
+                        &sendmail_cmd,
+                    )
+                }))
+                .unwrap_or_else(|_| Err(anyhow!("panicked while mirroring {name}")));
+                if result_tx.send((name, result)).is_err() {
+                    break;
+                }
+            })
+        })
+        .collect();
+    drop(result_tx);
+
+    let mut failures = 0;
+    for (name, result) in &result_rx {
+        if let Err(e) = result {
+            failures += 1;
+            eprintln!("[{name}] failed: {e:#}");
+        }
+    }
+    for worker in workers {
+        let _ = worker.join();
+    }
+
+    Ok(failures)
+}
+
+fn main() -> anyhow::Result<()> {
+    let args = Args::parse();
+
+    let mut failures = 0;
+    if let Some(config_path) = &args.config {
+        let config = load_config(config_path)?;
+        for entry in &config.entries {
+            let root = match &entry.subdir {
+                Some(subdir) => config.workspace.join(subdir),
+                None => config.workspace.clone(),
+            };
+            std::fs::create_dir_all(&root)
+                .with_context(|| format!("failed to create workspace dir {root:?}"))?;
+            failures += mirror_source(
+                entry.source()?,
+                &root,
+                args.host.as_deref(),
+                args.visibility.as_deref(),
+                args.affiliation.as_deref(),
+                args.dry_run,
+                &args.push_to,
+                args.jobs,
+                args.notify_to.as_deref(),
+                &args.sendmail_cmd,
+            )?;
+        }
+    } else {
+        let root = std::env::current_dir().context("failed to get cwd")?;
+        let source = if args.self_user {
+            Source::SelfUser
+        } else if let Some(org) = &args.org {
+            Source::Org(org)
+        } else {
+            Source::User(
+                args.user
+                    .as_deref()
+                    .expect("clap group 'u' requires one of user/self_user/config/org"),
+            )
+        };
+        failures = mirror_source(
+            source,
+            &root,
+            args.host.as_deref(),
+            args.visibility.as_deref(),
+            args.affiliation.as_deref(),
+            args.dry_run,
+            &args.push_to,
+            args.jobs,
+            args.notify_to.as_deref(),
+            &args.sendmail_cmd,
+        )?;
+    }
+
+    if failures > 0 {
+        return Err(anyhow!("{failures} repo(s) failed to mirror"));
     }
     Ok(())
 }